@@ -0,0 +1,146 @@
+// Copyright 2018 ARP Network
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::f64::consts::PI;
+
+const BASE83_CHARS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Encodes an sRGB `width` x `height` pixel buffer (3 bytes/pixel,
+/// row-major) into a compact BlurHash placeholder string, using a
+/// `components_x` x `components_y` grid of DCT coefficients.
+pub fn encode(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    components_x: usize,
+    components_y: usize,
+) -> String {
+    assert!(components_x >= 1 && components_x <= 9);
+    assert!(components_y >= 1 && components_y <= 9);
+
+    let mut factors = Vec::with_capacity(components_x * components_y);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalization = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            factors.push(dct_component(pixels, width, height, i, j, normalization));
+        }
+    }
+    let dc = factors[0];
+    let ac = &factors[1..];
+
+    let mut hash = String::new();
+
+    // Header: grid size.
+    let size_flag = (components_x - 1) + (components_y - 1) * 9;
+    push_base83(&mut hash, size_flag as u32, 1);
+
+    // Header: quantized maximum AC magnitude across all channels.
+    let max_ac = ac.iter().fold(0.0f64, |acc, &(r, g, b)| {
+        acc.max(r.abs()).max(g.abs()).max(b.abs())
+    });
+    let quantized_max_ac = if ac.is_empty() {
+        0
+    } else {
+        ((max_ac * 166.0 - 0.5).max(0.0).min(82.0)) as u32
+    };
+    push_base83(&mut hash, quantized_max_ac, 1);
+
+    // DC: the (0, 0) coefficient is the average color.
+    push_base83(&mut hash, encode_dc(dc), 4);
+
+    // AC: each coefficient quantized to 0..=18 per channel.
+    let max_ac_value = if ac.is_empty() {
+        1.0
+    } else {
+        (quantized_max_ac as f64 + 1.0) / 166.0
+    };
+    for &(r, g, b) in ac {
+        push_base83(&mut hash, encode_ac(r, g, b, max_ac_value), 2);
+    }
+
+    hash
+}
+
+/// Computes `c[i][j] = scale * sum(linear(pixel) * cos(pi*i*x/w) * cos(pi*j*y/h))`
+/// for all three channels at once.
+fn dct_component(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    i: usize,
+    j: usize,
+    normalization: f64,
+) -> (f64, f64, f64) {
+    let mut r = 0.0;
+    let mut g = 0.0;
+    let mut b = 0.0;
+    for y in 0..height {
+        let cos_y = (PI * j as f64 * y as f64 / height as f64).cos();
+        for x in 0..width {
+            let basis = (PI * i as f64 * x as f64 / width as f64).cos() * cos_y;
+            let offset = (y * width + x) * 3;
+            r += basis * srgb_to_linear(pixels[offset]);
+            g += basis * srgb_to_linear(pixels[offset + 1]);
+            b += basis * srgb_to_linear(pixels[offset + 2]);
+        }
+    }
+    let scale = normalization / (width * height) as f64;
+    (r * scale, g * scale, b * scale)
+}
+
+/// Converts an 8-bit sRGB channel value to linear light.
+fn srgb_to_linear(value: u8) -> f64 {
+    let v = value as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linear channel value back to an 8-bit sRGB value.
+fn linear_to_srgb(value: f64) -> u32 {
+    let v = value.max(0.0).min(1.0);
+    let srgb = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0 + 0.5).max(0.0).min(255.0) as u32
+}
+
+/// Packs the DC average color into a single 24-bit RGB value.
+fn encode_dc(dc: (f64, f64, f64)) -> u32 {
+    (linear_to_srgb(dc.0) << 16) | (linear_to_srgb(dc.1) << 8) | linear_to_srgb(dc.2)
+}
+
+/// Quantizes one AC coefficient's three channels to 0..=18 each and packs
+/// them as a single base-19 value (below 19^3), which fits in two base-83
+/// characters.
+fn encode_ac(r: f64, g: f64, b: f64, max_value: f64) -> u32 {
+    let quantize = |v: f64| -> u32 {
+        (sign_pow(v / max_value, 0.5) * 9.0 + 9.5).max(0.0).min(18.0) as u32
+    };
+    quantize(r) * 19 * 19 + quantize(g) * 19 + quantize(b)
+}
+
+/// `sign(value) * |value|^exp`.
+fn sign_pow(value: f64, exp: f64) -> f64 {
+    value.abs().powf(exp) * value.signum()
+}
+
+/// Encodes `value` as `length` base-83 characters.
+fn push_base83(out: &mut String, mut value: u32, length: usize) {
+    let mut chars = vec![0u8; length];
+    for i in (0..length).rev() {
+        chars[i] = BASE83_CHARS[(value % 83) as usize];
+        value /= 83;
+    }
+    out.push_str(::std::str::from_utf8(&chars).unwrap());
+}
@@ -0,0 +1,86 @@
+// Copyright 2018 ARP Network
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{self, Write};
+use std::net::TcpStream;
+
+/// A reverse-input event captured from the viewer and forwarded to the
+/// remote device, mirroring the framebuffer-input model: an event-type
+/// byte followed by a handful of fixed-size fields.
+#[derive(Clone, Copy, Debug)]
+pub enum InputEvent {
+    MouseDown { x: u16, y: u16, button: u8 },
+    MouseUp { x: u16, y: u16, button: u8 },
+    MouseMove { x: u16, y: u16 },
+    KeyDown { keysym: u32 },
+    KeyUp { keysym: u32 },
+    TouchDown { id: i64, x: u16, y: u16 },
+    TouchMove { id: i64, x: u16, y: u16 },
+    TouchUp { id: i64, x: u16, y: u16 },
+}
+
+impl InputEvent {
+    fn type_byte(&self) -> u8 {
+        match *self {
+            InputEvent::MouseDown { .. } => 0,
+            InputEvent::MouseUp { .. } => 1,
+            InputEvent::MouseMove { .. } => 2,
+            InputEvent::KeyDown { .. } => 3,
+            InputEvent::KeyUp { .. } => 4,
+            InputEvent::TouchDown { .. } => 5,
+            InputEvent::TouchMove { .. } => 6,
+            InputEvent::TouchUp { .. } => 7,
+        }
+    }
+
+    /// Encodes this event as `|1 byte type| 1 byte size| <size> bytes payload|`,
+    /// x/y always in the source frame's native pixel space.
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match *self {
+            InputEvent::MouseDown { x, y, button } | InputEvent::MouseUp { x, y, button } => {
+                payload.extend_from_slice(&x.to_le_bytes());
+                payload.extend_from_slice(&y.to_le_bytes());
+                payload.push(button);
+            }
+            InputEvent::MouseMove { x, y } => {
+                payload.extend_from_slice(&x.to_le_bytes());
+                payload.extend_from_slice(&y.to_le_bytes());
+            }
+            InputEvent::KeyDown { keysym } | InputEvent::KeyUp { keysym } => {
+                payload.extend_from_slice(&keysym.to_le_bytes());
+            }
+            InputEvent::TouchDown { id, x, y }
+            | InputEvent::TouchMove { id, x, y }
+            | InputEvent::TouchUp { id, x, y } => {
+                payload.extend_from_slice(&id.to_le_bytes());
+                payload.extend_from_slice(&x.to_le_bytes());
+                payload.extend_from_slice(&y.to_le_bytes());
+            }
+        }
+        let mut out = Vec::with_capacity(2 + payload.len());
+        out.push(self.type_byte());
+        out.push(payload.len() as u8);
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    /// Writes this event back on the connection's stream.
+    pub fn send(&self, stream: &mut TcpStream) -> io::Result<()> {
+        stream.write_all(&self.encode())
+    }
+}
+
+/// Translates a point in the displayed window (`disp_w` x `disp_h`) into
+/// the frame's native resolution (`native_w` x `native_h`), undoing any
+/// downscaling applied when the window was created.
+pub fn to_native(x: i32, y: i32, disp_w: i32, disp_h: i32, native_w: i32, native_h: i32) -> (u16, u16) {
+    let nx = if disp_w > 0 { x * native_w / disp_w } else { x };
+    let ny = if disp_h > 0 { y * native_h / disp_h } else { y };
+    (nx.max(0) as u16, ny.max(0) as u16)
+}
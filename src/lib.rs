@@ -0,0 +1,18 @@
+// Copyright 2018 ARP Network
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+mod blurhash;
+mod input;
+mod recorder;
+mod ts;
+mod window;
+
+pub use input::{to_native, InputEvent};
+pub use recorder::Recorder;
+pub use ts::{to_pcr_clock, TsMuxer};
+pub use window::{Codec, VideoWindow};
@@ -10,14 +10,17 @@ extern crate arplay;
 extern crate bytes;
 extern crate sdl2;
 
-use arplay::H264Window;
+use arplay::{to_native, to_pcr_clock, Codec, InputEvent, TsMuxer, VideoWindow};
 
 use bytes::{Buf, IntoBuf};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 
 use std::collections::HashMap;
+use std::env;
+use std::fs::File;
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
 use std::sync::{mpsc, mpsc::Sender};
@@ -26,16 +29,25 @@ use std::time::{Duration, Instant};
 use std::{io, io::prelude::*};
 
 enum Msg {
-    New(i32, TcpStream),
+    New(i32, Codec, TcpStream),
     Data(i32, Vec<u8>),
     End(i32),
 }
 
-type WindowMap = HashMap<i32, (H264Window, Instant)>;
+/// Per-connection window state: the decoder/display, its creation time
+/// (for sort order), and a writable clone of its socket used to forward
+/// input events back to the remote device.
+type WindowMap = HashMap<i32, (VideoWindow, Instant, TcpStream)>;
+
+/// Per-connection MPEG-TS sink: the muxer, its output file, and the clock
+/// used to derive 90 kHz PTS values.
+type TsMap = HashMap<i32, (TsMuxer, File, Instant)>;
 
 pub fn main() {
     let sdl = sdl2::init().unwrap();
     let mut windows = HashMap::new();
+    let mut ts_sinks = HashMap::new();
+    let ts_dir = env::var("ARPLAY_TS_DIR").ok();
     let (tx, rx) = mpsc::channel();
 
     // Gets the size of screen
@@ -45,6 +57,10 @@ pub fn main() {
 
     spawn_listener(1218, tx.clone());
 
+    // SDL touch events carry no `window_id`, so track whichever window
+    // last saw a mouse/keyboard event and route touches there instead.
+    let mut focused_window: Option<u32> = None;
+
     let mut event_pump = sdl.event_pump().unwrap();
     'running: loop {
         // Handles GUI events
@@ -55,6 +71,87 @@ pub fn main() {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
+                Event::KeyDown {
+                    window_id,
+                    keycode: Some(Keycode::R),
+                    ..
+                } => {
+                    focused_window = Some(window_id);
+                    toggle_recording(&mut windows, window_id);
+                }
+                Event::KeyDown {
+                    window_id,
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    focused_window = Some(window_id);
+                    send_input(&mut windows, window_id, InputEvent::KeyDown {
+                        keysym: keycode as i32 as u32,
+                    });
+                }
+                Event::KeyUp {
+                    window_id,
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    focused_window = Some(window_id);
+                    send_input(&mut windows, window_id, InputEvent::KeyUp {
+                        keysym: keycode as i32 as u32,
+                    });
+                }
+                Event::MouseButtonDown {
+                    window_id, x, y, mouse_btn, ..
+                } => {
+                    focused_window = Some(window_id);
+                    if let Some((nx, ny)) = native_point(&windows, window_id, x, y) {
+                        send_input(&mut windows, window_id, InputEvent::MouseDown {
+                            x: nx,
+                            y: ny,
+                            button: button_mask(mouse_btn),
+                        });
+                    }
+                }
+                Event::MouseButtonUp {
+                    window_id, x, y, mouse_btn, ..
+                } => {
+                    focused_window = Some(window_id);
+                    if let Some((nx, ny)) = native_point(&windows, window_id, x, y) {
+                        send_input(&mut windows, window_id, InputEvent::MouseUp {
+                            x: nx,
+                            y: ny,
+                            button: button_mask(mouse_btn),
+                        });
+                    }
+                }
+                Event::MouseMotion {
+                    window_id, x, y, ..
+                } => {
+                    focused_window = Some(window_id);
+                    if let Some((nx, ny)) = native_point(&windows, window_id, x, y) {
+                        send_input(&mut windows, window_id, InputEvent::MouseMove { x: nx, y: ny });
+                    }
+                }
+                Event::FingerDown {
+                    finger_id, x, y, ..
+                } => {
+                    if let Some(window_id) = focused_window {
+                        send_touch(&mut windows, window_id, finger_id, x, y, TouchPhase::Down);
+                    }
+                }
+                Event::FingerMotion {
+                    finger_id, x, y, ..
+                } => {
+                    if let Some(window_id) = focused_window {
+                        send_touch(&mut windows, window_id, finger_id, x, y, TouchPhase::Move);
+                    }
+                }
+                Event::FingerUp {
+                    finger_id, x, y, ..
+                } => {
+                    if let Some(window_id) = focused_window {
+                        send_touch(&mut windows, window_id, finger_id, x, y, TouchPhase::Up);
+                    }
+                }
                 _ => {}
             }
         }
@@ -62,28 +159,51 @@ pub fn main() {
         // Handles streaming events
         if let Ok(msg) = rx.recv_timeout(Duration::from_millis(1000 / 60)) {
             match msg {
-                Msg::New(fd, s) => {
+                Msg::New(fd, codec, s) => {
                     let name = peer_addr(&s);
-                    let win = H264Window::new(&name, sdl.video().unwrap());
-                    windows.insert(fd, (win, Instant::now()));
-                    spawn_streaming(fd, s, tx.clone());
+                    match VideoWindow::new(&name, codec, sdl.video().unwrap(), screen_w, screen_h) {
+                        Some(win) => {
+                            let writer = s.try_clone().unwrap();
+                            windows.insert(fd, (win, Instant::now(), writer));
+                            if let Some(ref dir) = ts_dir {
+                                if codec == Codec::H264 {
+                                    if let Some(sink) = open_ts_sink(dir, fd) {
+                                        ts_sinks.insert(fd, sink);
+                                    }
+                                } else {
+                                    eprintln!("{}: TS tee of a {:?} stream isn't supported, skipping", name, codec);
+                                }
+                            }
+                            spawn_streaming(fd, s, tx.clone());
+                        }
+                        None => eprintln!("{}: no decoder for {:?}, dropping connection", name, codec),
+                    }
                 }
                 Msg::Data(fd, mut data) => {
                     let mut is_new = false;
-                    if let Some((ref mut win, _)) = windows.get_mut(&fd) {
+                    let mut decode_failed = false;
+                    if let Some((ref mut win, _, _)) = windows.get_mut(&fd) {
                         is_new = !win.is_shown();
-                        win.draw(data.as_mut());
+                        if let Err(err) = win.draw(data.as_mut()) {
+                            eprintln!("decode error on fd {}: {}", fd, err);
+                            decode_failed = true;
+                        }
+                    }
+                    if let Some((ref mut muxer, ref mut file, ref started)) =
+                        ts_sinks.get_mut(&fd)
+                    {
+                        let pts = to_pcr_clock(started.elapsed().as_nanos() as u64);
+                        let _ = muxer.write_frame(&data, pts, is_keyframe(&data), file);
                     }
                     if is_new {
                         align_windows(&mut windows, screen_w, screen_h);
                     }
+                    if decode_failed {
+                        close_connection(&mut windows, &mut ts_sinks, fd, screen_w, screen_h);
+                    }
                 }
                 Msg::End(fd) => {
-                    windows.remove(&fd).and_then(|(ref mut win, _)| {
-                        win.hide();
-                        align_windows(&mut windows, screen_w, screen_h);
-                        Some(())
-                    });
+                    close_connection(&mut windows, &mut ts_sinks, fd, screen_w, screen_h);
                 }
             }
         }
@@ -97,14 +217,28 @@ fn spawn_listener(port: u16, tx: Sender<Msg>) {
         let listener = TcpListener::bind(addr).unwrap();
 
         for stream in listener.incoming() {
-            if let Ok(s) = stream {
+            if let Ok(mut s) = stream {
                 let fd = s.as_raw_fd();
-                tx.send(Msg::New(fd, s)).unwrap();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    if let Ok(codec) = read_codec(&mut s) {
+                        tx.send(Msg::New(fd, codec, s)).unwrap();
+                    }
+                });
             }
         }
     });
 }
 
+/// Reads the per-connection codec handshake: a single 4-byte FourCC
+/// (`h264`, `hvc1`/`hev1`, or `vp09`) sent before any video data.
+fn read_codec(s: &mut TcpStream) -> io::Result<Codec> {
+    let mut tag = [0; 4];
+    s.read_exact(&mut tag)?;
+    Codec::from_fourcc(&tag)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown codec tag"))
+}
+
 /// Spawns a new TCP streaming thread.
 fn spawn_streaming(fd: i32, mut s: TcpStream, tx: Sender<Msg>) {
     thread::spawn(move || loop {
@@ -128,6 +262,126 @@ fn peer_addr(s: &TcpStream) -> String {
     }
 }
 
+/// Toggles MP4 recording for the window matching `window_id`, if any.
+fn toggle_recording(windows: &mut WindowMap, window_id: u32) {
+    for (fd, (win, _, _)) in windows.iter_mut() {
+        if win.id() == Some(window_id) {
+            if win.is_recording() {
+                win.stop_recording();
+            } else {
+                win.start_recording(&format!("capture-{}.mp4", fd));
+            }
+            break;
+        }
+    }
+}
+
+/// Flushes and tears down a connection's window and TS sink, then
+/// realigns the remaining windows.
+fn close_connection(
+    windows: &mut WindowMap,
+    ts_sinks: &mut TsMap,
+    fd: i32,
+    screen_w: i32,
+    screen_h: i32,
+) {
+    ts_sinks.remove(&fd);
+    windows.remove(&fd).and_then(|(ref mut win, _, _)| {
+        win.flush();
+        win.hide();
+        align_windows(windows, screen_w, screen_h);
+        Some(())
+    });
+}
+
+/// Translates a window-local point from displayed (possibly scaled) pixel
+/// space into the source frame's native resolution.
+fn native_point(windows: &WindowMap, window_id: u32, x: i32, y: i32) -> Option<(u16, u16)> {
+    windows.values().find_map(|(win, _, _)| {
+        if win.id() != Some(window_id) {
+            return None;
+        }
+        let (disp_w, disp_h) = win.size();
+        let (native_w, native_h) = win.native_size();
+        Some(to_native(x, y, disp_w, disp_h, native_w, native_h))
+    })
+}
+
+/// Encodes an SDL mouse button as a single-bit mask, per the
+/// framebuffer-input convention.
+fn button_mask(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 0x1,
+        MouseButton::Middle => 0x2,
+        MouseButton::Right => 0x4,
+        _ => 0,
+    }
+}
+
+/// Which part of a touch gesture an SDL finger event represents.
+enum TouchPhase {
+    Down,
+    Move,
+    Up,
+}
+
+/// Converts a normalized (0.0..1.0) finger event into the frame's native
+/// pixel space and forwards it to the matching window.
+fn send_touch(windows: &mut WindowMap, window_id: u32, finger_id: i64, x: f32, y: f32, phase: TouchPhase) {
+    let native = windows.values().find_map(|(win, _, _)| {
+        if win.id() != Some(window_id) {
+            return None;
+        }
+        let (w, h) = win.native_size();
+        Some(((x * w as f32) as u16, (y * h as f32) as u16))
+    });
+    if let Some((nx, ny)) = native {
+        let event = match phase {
+            TouchPhase::Down => InputEvent::TouchDown { id: finger_id, x: nx, y: ny },
+            TouchPhase::Move => InputEvent::TouchMove { id: finger_id, x: nx, y: ny },
+            TouchPhase::Up => InputEvent::TouchUp { id: finger_id, x: nx, y: ny },
+        };
+        send_input(windows, window_id, event);
+    }
+}
+
+/// Forwards `event` back to the remote device over the window's socket.
+fn send_input(windows: &mut WindowMap, window_id: u32, event: InputEvent) {
+    for (win, _, stream) in windows.values_mut() {
+        if win.id() == Some(window_id) {
+            let _ = event.send(stream);
+            break;
+        }
+    }
+}
+
+/// Opens the `<dir>/<fd>.ts` output file and a fresh muxer for a new
+/// connection, if `ARPLAY_TS_DIR` is set.
+fn open_ts_sink(dir: &str, fd: i32) -> Option<(TsMuxer, File, Instant)> {
+    let path = format!("{}/{}.ts", dir, fd);
+    File::create(path)
+        .ok()
+        .map(|file| (TsMuxer::new(), file, Instant::now()))
+}
+
+/// Returns `true` if the Annex B access unit contains an IDR (keyframe)
+/// NAL unit (type 5).
+fn is_keyframe(data: &[u8]) -> bool {
+    let mut i = 0;
+    while i + 3 < data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            let nal_type = data[i + 3] & 0x1F;
+            if nal_type == 5 {
+                return true;
+            }
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    false
+}
+
 /// Windows padding
 const PADDING: i32 = 20;
 
@@ -136,7 +390,7 @@ fn align_windows(windows: &mut WindowMap, width: i32, height: i32) {
     let size = windows.len() as i32;
     let w = windows
         .iter()
-        .fold(0, |acc, (_, (win, _))| acc + win.width()) + (size - 1) * PADDING;
+        .fold(0, |acc, (_, (win, _, _))| acc + win.width()) + (size - 1) * PADDING;
     let mut x = (width - w) / 2;
 
     // Sorts by created time
@@ -144,10 +398,10 @@ fn align_windows(windows: &mut WindowMap, width: i32, height: i32) {
     for (_, item) in windows.iter_mut() {
         items.push(item);
     }
-    items.sort_by(|(_, a), (_, b)| a.cmp(&b));
+    items.sort_by(|(_, a, _), (_, b, _)| a.cmp(&b));
 
     // Repositions windows
-    for (ref mut win, _) in items.iter_mut() {
+    for (ref mut win, _, _) in items.iter_mut() {
         let (ww, wh) = win.size();
         win.set_position(x, (height - wh) / 2);
         x += ww + PADDING;
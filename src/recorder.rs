@@ -0,0 +1,246 @@
+// Copyright 2018 ARP Network
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate ffmpeg_sys as ffmpeg;
+
+use self::ffmpeg::*;
+
+use std::ffi::CString;
+use std::ptr;
+use std::time::Instant;
+
+/// An opened MP4 output: the format context, the video stream's index,
+/// and its timebase (used to rescale packet timestamps).
+struct Muxer {
+    ctx: *mut AVFormatContext,
+    stream_index: i32,
+    timebase: AVRational,
+}
+
+/// Remuxes raw Annex-B access units into an MP4 file without re-encoding.
+/// MP4 (`movenc`) requires length-prefixed AVCC NAL units and an `avcC`
+/// extradata box built from SPS/PPS, neither of which a raw Annex-B wire
+/// stream provides up front, so the muxer is opened lazily on the first
+/// access unit that actually carries parameter sets.
+pub struct Recorder {
+    path: CString,
+    codec_id: AVCodecID,
+    width: i32,
+    height: i32,
+    muxer: Option<Muxer>,
+    started_at: Instant,
+}
+
+impl Recorder {
+    /// Prepares a `Recorder` that will open `path` for `codec_id` once the
+    /// first access unit carrying parameter sets (SPS/PPS) arrives. If
+    /// `extradata` (the decoder's parameter sets) is already available,
+    /// opens immediately instead.
+    pub fn new(path: &str, codec_id: AVCodecID, width: i32, height: i32, extradata: &[u8]) -> Option<Recorder> {
+        let path = CString::new(path).ok()?;
+        let mut recorder = Recorder {
+            path,
+            codec_id,
+            width,
+            height,
+            muxer: None,
+            started_at: Instant::now(),
+        };
+        if !extradata.is_empty() {
+            if !recorder.open(extradata) {
+                return None;
+            }
+        }
+        Some(recorder)
+    }
+
+    /// Allocates the output context, stream and `avcC` extradata, and
+    /// writes the MP4 header. Returns `false` on any ffmpeg failure.
+    fn open(&mut self, extradata: &[u8]) -> bool {
+        unsafe {
+            let mut ctx: *mut AVFormatContext = ptr::null_mut();
+            let format = CString::new("mp4").unwrap();
+            let ret = avformat_alloc_output_context2(
+                &mut ctx,
+                ptr::null_mut(),
+                format.as_ptr(),
+                self.path.as_ptr(),
+            );
+            if ret < 0 || ctx == ptr::null_mut() {
+                return false;
+            }
+
+            let codec = avcodec_find_decoder(self.codec_id);
+            let stream = avformat_new_stream(ctx, codec);
+            if stream == ptr::null_mut() {
+                avformat_free_context(ctx);
+                return false;
+            }
+
+            let codecpar = (*stream).codecpar;
+            (*codecpar).codec_type = AVMediaType::AVMEDIA_TYPE_VIDEO;
+            (*codecpar).codec_id = self.codec_id;
+            (*codecpar).width = self.width;
+            (*codecpar).height = self.height;
+            let buf = av_malloc(extradata.len() + AV_INPUT_BUFFER_PADDING_SIZE as usize);
+            ptr::copy_nonoverlapping(extradata.as_ptr(), buf as *mut u8, extradata.len());
+            (*codecpar).extradata = buf as *mut u8;
+            (*codecpar).extradata_size = extradata.len() as i32;
+
+            let timebase = AVRational { num: 1, den: 90_000 };
+            (*stream).time_base = timebase;
+
+            if (*(*ctx).oformat).flags & AVFMT_NOFILE == 0 {
+                let ret = avio_open(&mut (*ctx).pb, self.path.as_ptr(), AVIO_FLAG_WRITE);
+                if ret < 0 {
+                    avformat_free_context(ctx);
+                    return false;
+                }
+            }
+
+            let ret = avformat_write_header(ctx, ptr::null_mut());
+            if ret < 0 {
+                avformat_free_context(ctx);
+                return false;
+            }
+
+            self.muxer = Some(Muxer {
+                ctx,
+                stream_index: (*stream).index,
+                timebase,
+            });
+            true
+        }
+    }
+
+    /// Converts one Annex-B access unit to AVCC and writes it, rescaling
+    /// its timestamp from the monotonic clock to the stream's timebase.
+    /// Until the muxer has been opened, access units are scanned for
+    /// SPS/PPS and otherwise dropped.
+    pub fn write_packet(&mut self, data: &[u8]) {
+        let mut avcc = Vec::with_capacity(data.len() + 16);
+        let nals = annexb_to_avcc(data, &mut avcc);
+        let keyframe = nals.iter().any(|n| !n.is_empty() && n[0] & 0x1F == 5);
+
+        if self.muxer.is_none() {
+            match sps_pps(&nals) {
+                Some((sps, pps)) => {
+                    if !self.open(&avcc_extradata(sps, pps)) {
+                        eprintln!("recorder: failed to open MP4 output");
+                        return;
+                    }
+                }
+                None => return,
+            }
+        }
+
+        let muxer = self.muxer.as_ref().unwrap();
+        unsafe {
+            let pkt = av_packet_alloc();
+            av_init_packet(pkt);
+            if av_new_packet(pkt, avcc.len() as i32) < 0 {
+                av_packet_free(&mut { pkt });
+                return;
+            }
+            ptr::copy_nonoverlapping(avcc.as_ptr(), (*pkt).data, avcc.len());
+            (*pkt).stream_index = muxer.stream_index;
+
+            let us = self.started_at.elapsed().as_micros() as i64;
+            let us_timebase = AVRational { num: 1, den: 1_000_000 };
+            let pts = av_rescale_q(us, us_timebase, muxer.timebase);
+            (*pkt).pts = pts;
+            (*pkt).dts = pts;
+            if keyframe {
+                (*pkt).flags |= AV_PKT_FLAG_KEY;
+            }
+
+            let ret = av_interleaved_write_frame(muxer.ctx, pkt);
+            if ret < 0 {
+                eprintln!("recorder: av_interleaved_write_frame failed: {}", ret);
+            }
+            av_packet_free(&mut { pkt });
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(ref muxer) = self.muxer {
+            unsafe {
+                av_write_trailer(muxer.ctx);
+                if (*(*muxer.ctx).oformat).flags & AVFMT_NOFILE == 0 {
+                    avio_closep(&mut (*muxer.ctx).pb);
+                }
+                avformat_free_context(muxer.ctx);
+            }
+        }
+    }
+}
+
+/// Splits an Annex-B access unit (`00 00 01`/`00 00 00 01`-prefixed NAL
+/// units) into individual NAL unit byte slices.
+fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 2 < data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut nals = Vec::with_capacity(starts.len());
+    for (idx, &start) in starts.iter().enumerate() {
+        // `starts[idx + 1]` points past the *next* NAL's start code, so
+        // back up over its 3-byte "00 00 01" tail to stop before that
+        // code rather than inside it.
+        let mut end = starts.get(idx + 1).map(|&s| s - 3).unwrap_or(data.len());
+        // Trim the 0x00 that precedes a 4-byte start code belonging to
+        // the next NAL unit.
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            nals.push(&data[start..end]);
+        }
+    }
+    nals
+}
+
+/// Rewrites an Annex-B access unit as AVCC (4-byte big-endian length
+/// prefixes instead of start codes) into `out`, returning the individual
+/// NAL units found along the way.
+fn annexb_to_avcc<'a>(data: &'a [u8], out: &mut Vec<u8>) -> Vec<&'a [u8]> {
+    let nals = split_annexb(data);
+    for nal in &nals {
+        out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        out.extend_from_slice(nal);
+    }
+    nals
+}
+
+/// Finds the first SPS (type 7) and PPS (type 8) NAL units, if both are
+/// present.
+fn sps_pps<'a>(nals: &[&'a [u8]]) -> Option<(&'a [u8], &'a [u8])> {
+    let sps = nals.iter().find(|n| !n.is_empty() && n[0] & 0x1F == 7)?;
+    let pps = nals.iter().find(|n| !n.is_empty() && n[0] & 0x1F == 8)?;
+    Some((sps, pps))
+}
+
+/// Builds an `avcC` extradata box from one SPS/PPS pair.
+fn avcc_extradata(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut out = vec![1, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+    out.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    out.extend_from_slice(sps);
+    out.push(1);
+    out.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    out.extend_from_slice(pps);
+    out
+}
@@ -0,0 +1,250 @@
+// Copyright 2018 ARP Network
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{self, Write};
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 4096;
+const VIDEO_PID: u16 = 256;
+const PCR_HZ: i64 = 90_000;
+
+/// Packetizes raw H.264 access units into an MPEG-TS elementary stream, so
+/// a session can be forwarded to an HLS segmenter or another TS consumer
+/// instead of only rendered locally.
+pub struct TsMuxer {
+    pat_cc: u8,
+    pmt_cc: u8,
+    video_cc: u8,
+}
+
+impl TsMuxer {
+    /// Constructs a new `TsMuxer`.
+    pub fn new() -> TsMuxer {
+        TsMuxer {
+            pat_cc: 0,
+            pmt_cc: 0,
+            video_cc: 0,
+        }
+    }
+
+    /// Packetizes one H.264 access unit timestamped at `pts_90k` (a 90 kHz
+    /// clock) and writes the resulting TS packets to `out`. The PAT/PMT
+    /// are (re-)sent ahead of every keyframe so a consumer can join the
+    /// stream mid-way.
+    pub fn write_frame<W: Write>(
+        &mut self,
+        data: &[u8],
+        pts_90k: i64,
+        keyframe: bool,
+        out: &mut W,
+    ) -> io::Result<()> {
+        if keyframe {
+            out.write_all(&self.pat_packet())?;
+            out.write_all(&self.pmt_packet())?;
+        }
+        self.write_pes(data, pts_90k, keyframe, out)
+    }
+
+    /// Builds the Program Association Table packet (program 1 -> PMT PID).
+    fn pat_packet(&mut self) -> [u8; PACKET_SIZE] {
+        let mut section = Vec::new();
+        section.push(0x00); // table_id: program_association_section
+        section.extend_from_slice(&[0xB0, 0x0D]); // section_syntax_indicator + section_length
+        section.extend_from_slice(&[0x00, 0x01]); // transport_stream_id
+        section.push(0xC1); // version_number / current_next_indicator
+        section.push(0x00); // section_number
+        section.push(0x00); // last_section_number
+        section.extend_from_slice(&[0x00, 0x01]); // program_number 1
+        section.push(0xE0 | ((PMT_PID >> 8) as u8));
+        section.push((PMT_PID & 0xFF) as u8);
+        section.extend_from_slice(&crc32(&section).to_be_bytes());
+
+        let cc = next_cc(&mut self.pat_cc);
+        section_packet(PAT_PID, cc, &section)
+    }
+
+    /// Builds the Program Map Table packet, declaring a single H.264
+    /// (stream_type 0x1B) elementary stream on `VIDEO_PID`.
+    fn pmt_packet(&mut self) -> [u8; PACKET_SIZE] {
+        let mut section = Vec::new();
+        section.push(0x02); // table_id: TS_program_map_section
+        section.extend_from_slice(&[0xB0, 0x12]); // section_syntax_indicator + section_length
+        section.extend_from_slice(&[0x00, 0x01]); // program_number
+        section.push(0xC1); // version_number / current_next_indicator
+        section.push(0x00); // section_number
+        section.push(0x00); // last_section_number
+        section.push(0xE0 | ((VIDEO_PID >> 8) as u8)); // PCR_PID
+        section.push((VIDEO_PID & 0xFF) as u8);
+        section.extend_from_slice(&[0xF0, 0x00]); // program_info_length = 0
+        section.push(0x1B); // stream_type: H.264
+        section.push(0xE0 | ((VIDEO_PID >> 8) as u8));
+        section.push((VIDEO_PID & 0xFF) as u8);
+        section.extend_from_slice(&[0xF0, 0x00]); // ES_info_length = 0
+        section.extend_from_slice(&crc32(&section).to_be_bytes());
+
+        let cc = next_cc(&mut self.pmt_cc);
+        section_packet(PMT_PID, cc, &section)
+    }
+
+    /// Wraps `data` in a PES packet (stream_id 0xE0) and splits it across
+    /// as many TS payloads as needed, setting `payload_unit_start_indicator`
+    /// on the first. Keyframes carry an adaptation field with a PCR
+    /// derived from `pts_90k`.
+    fn write_pes<W: Write>(
+        &mut self,
+        data: &[u8],
+        pts_90k: i64,
+        keyframe: bool,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let mut pes = Vec::with_capacity(data.len() + 19);
+        pes.extend_from_slice(&[0x00, 0x00, 0x01, 0xE0]); // packet_start_code_prefix + stream_id
+        pes.extend_from_slice(&[0x00, 0x00]); // PES_packet_length (0: unbounded, video)
+        pes.push(0x80); // '10' + flags
+        pes.push(0xC0); // PTS_DTS_flags = '11' (both present)
+        pes.push(0x0A); // PES_header_data_length (2 timestamps, 5 bytes each)
+        pes.extend_from_slice(&pts_dts_bytes(0x3, pts_90k));
+        pes.extend_from_slice(&pts_dts_bytes(0x1, pts_90k));
+        pes.extend_from_slice(data);
+
+        let mut first = true;
+        let mut offset = 0;
+        while offset < pes.len() {
+            let cc = next_cc(&mut self.video_cc);
+            let pcr = if first && keyframe { Some(pts_90k) } else { None };
+            let (packet, written) = payload_packet(VIDEO_PID, cc, first, pcr, &pes[offset..]);
+            out.write_all(&packet)?;
+            offset += written;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Advances and returns a 4-bit continuity counter.
+fn next_cc(cc: &mut u8) -> u8 {
+    let v = *cc;
+    *cc = (*cc + 1) & 0x0F;
+    v
+}
+
+/// Wraps a single PSI `section` (PAT/PMT) in one TS packet.
+fn section_packet(pid: u16, cc: u8, section: &[u8]) -> [u8; PACKET_SIZE] {
+    let mut packet = [0xFF; PACKET_SIZE];
+    packet[0] = SYNC_BYTE;
+    packet[1] = 0x40 | ((pid >> 8) as u8 & 0x1F); // payload_unit_start_indicator
+    packet[2] = (pid & 0xFF) as u8;
+    packet[3] = 0x10 | cc; // no adaptation field, payload only
+    packet[4] = 0x00; // pointer_field
+    packet[5..5 + section.len()].copy_from_slice(section);
+    packet
+}
+
+/// Wraps one TS-packet's worth of `payload` (possibly continuation data),
+/// returning the packet and the number of payload bytes consumed.
+fn payload_packet(
+    pid: u16,
+    cc: u8,
+    start: bool,
+    pcr_90k: Option<i64>,
+    payload: &[u8],
+) -> ([u8; PACKET_SIZE], usize) {
+    let mut packet = [0xFF; PACKET_SIZE];
+    packet[0] = SYNC_BYTE;
+    packet[1] = ((start as u8) << 6) | ((pid >> 8) as u8 & 0x1F);
+    packet[2] = (pid & 0xFF) as u8;
+
+    let header_len = match pcr_90k {
+        Some(pts) => {
+            packet[3] = 0x30 | cc; // adaptation field + payload
+            packet[4] = 7; // adaptation_field_length
+            packet[5] = 0x10; // PCR_flag
+            write_pcr(&mut packet[6..12], pts);
+            4 + 1 + 7
+        }
+        None => {
+            packet[3] = 0x10 | cc; // payload only
+            4
+        }
+    };
+
+    let available = PACKET_SIZE - header_len;
+    let n = payload.len().min(available);
+    packet[header_len..header_len + n].copy_from_slice(&payload[..n]);
+
+    // Stuff a short packet with adaptation field padding. The payload
+    // always ends flush with the packet, i.e. occupies the final `n`
+    // bytes `[PACKET_SIZE - n, PACKET_SIZE)`; the gap it leaves behind is
+    // refilled with `0xFF` stuffing bytes.
+    if n < available {
+        let pad = available - n;
+        packet[3] = 0x30 | cc;
+        packet.copy_within(header_len..header_len + n, header_len + pad);
+        for b in &mut packet[header_len..header_len + pad] {
+            *b = 0xFF;
+        }
+        match pcr_90k {
+            Some(_) => packet[4] = (7 + pad) as u8, // flags + PCR + stuffing
+            None => {
+                packet[4] = (pad - 1) as u8;
+                if pad > 1 {
+                    packet[5] = 0x00;
+                }
+            }
+        }
+    }
+    (packet, n)
+}
+
+/// Encodes a 33-bit PTS/DTS with its `prefix` marker bits, per PES syntax.
+fn pts_dts_bytes(prefix: u8, ts_90k: i64) -> [u8; 5] {
+    let ts = ts_90k as u64 & 0x1_FFFF_FFFF;
+    [
+        (prefix << 4) | (((ts >> 30) & 0x07) as u8) << 1 | 1,
+        ((ts >> 22) & 0xFF) as u8,
+        (((ts >> 15) & 0x7F) as u8) << 1 | 1,
+        ((ts >> 7) & 0xFF) as u8,
+        ((ts & 0x7F) as u8) << 1 | 1,
+    ]
+}
+
+/// Writes a 90 kHz `pts` as a 33-bit base + 9-bit (zero) extension PCR.
+fn write_pcr(out: &mut [u8], pts_90k: i64) {
+    let base = (pts_90k as u64) & 0x1_FFFF_FFFF;
+    let ext: u64 = 0;
+    out[0] = (base >> 25) as u8;
+    out[1] = (base >> 17) as u8;
+    out[2] = (base >> 9) as u8;
+    out[3] = (base >> 1) as u8;
+    out[4] = (((base & 0x1) as u8) << 7) | 0x7E | ((ext >> 8) as u8 & 0x01);
+    out[5] = (ext & 0xFF) as u8;
+}
+
+/// Computes the MPEG-2 CRC32 used to terminate PSI sections.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Converts a monotonic nanosecond duration into the 90 kHz clock used by
+/// MPEG-TS timestamps.
+pub fn to_pcr_clock(nanos: u64) -> i64 {
+    ((nanos as i128 * PCR_HZ as i128) / 1_000_000_000) as i64
+}
@@ -16,6 +16,9 @@ use self::sdl2::render::{Texture, WindowCanvas};
 use self::sdl2::video::WindowPos;
 use self::sdl2::VideoSubsystem;
 
+use blurhash;
+use recorder::Recorder;
+
 use std::error::Error;
 use std::ptr;
 use std::slice;
@@ -82,6 +85,11 @@ impl YUVWindow {
             .window_mut()
             .set_position(WindowPos::Positioned(x), WindowPos::Positioned(y));
     }
+
+    /// Returns the SDL window id, used to match GUI events to this window.
+    fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
 }
 
 struct YUVFrame {
@@ -106,6 +114,10 @@ impl YUVFrame {
         unsafe { (*self.raw).height }
     }
 
+    fn format(&self) -> i32 {
+        unsafe { (*self.raw).format }
+    }
+
     fn plane(&self, index: usize) -> &[u8] {
         unsafe {
             let frame = *self.raw;
@@ -128,48 +140,311 @@ impl Drop for YUVFrame {
     }
 }
 
-/// A Window which can draw with raw H.264 data directly.
-pub struct H264Window {
+/// Converts whatever pixel format/size the decoder emits into a
+/// fixed-size `AV_PIX_FMT_YUV420P` frame the `IYUV` texture can consume,
+/// caching the `SwsContext` for as long as the (src, dst) shape is
+/// unchanged.
+struct Converter {
+    ctx: *mut SwsContext,
+    src_fmt: i32,
+    src_w: i32,
+    src_h: i32,
+    dst: YUVFrame,
+}
+
+impl Converter {
+    fn new(src_fmt: i32, src_w: i32, src_h: i32, dst_w: i32, dst_h: i32) -> Option<Converter> {
+        unsafe {
+            let ctx = sws_getContext(
+                src_w,
+                src_h,
+                ::std::mem::transmute(src_fmt),
+                dst_w,
+                dst_h,
+                AVPixelFormat::AV_PIX_FMT_YUV420P,
+                SWS_BILINEAR as i32,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null(),
+            );
+            if ctx == ptr::null_mut() {
+                return None;
+            }
+
+            let dst = YUVFrame::new()?;
+            (*dst.raw).format = AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
+            (*dst.raw).width = dst_w;
+            (*dst.raw).height = dst_h;
+            if av_frame_get_buffer(dst.raw, 32) < 0 {
+                sws_freeContext(ctx);
+                return None;
+            }
+
+            Some(Converter {
+                ctx,
+                src_fmt,
+                src_w,
+                src_h,
+                dst,
+            })
+        }
+    }
+
+    /// Returns `true` if this converter was built for exactly this shape.
+    fn matches(&self, src_fmt: i32, src_w: i32, src_h: i32, dst_w: i32, dst_h: i32) -> bool {
+        self.src_fmt == src_fmt
+            && self.src_w == src_w
+            && self.src_h == src_h
+            && self.dst.width() == dst_w
+            && self.dst.height() == dst_h
+    }
+
+    /// Scales/converts `src` into the cached destination frame.
+    fn convert(&mut self, src: &YUVFrame) -> &YUVFrame {
+        unsafe {
+            sws_scale(
+                self.ctx,
+                (*src.raw).data.as_ptr() as *const *const u8,
+                (*src.raw).linesize.as_ptr(),
+                0,
+                self.src_h,
+                (*self.dst.raw).data.as_mut_ptr(),
+                (*self.dst.raw).linesize.as_mut_ptr(),
+            );
+        }
+        &self.dst
+    }
+}
+
+impl Drop for Converter {
+    fn drop(&mut self) {
+        unsafe { sws_freeContext(self.ctx) };
+    }
+}
+
+/// Converts a decoded frame of any pixel format into a tightly-packed
+/// sRGB24 buffer, for one-off consumers like `blurhash()` that don't need
+/// a cached `Converter`.
+fn frame_to_rgb(frame: &YUVFrame) -> Option<Vec<u8>> {
+    unsafe {
+        let (w, h) = (frame.width(), frame.height());
+        let ctx = sws_getContext(
+            w,
+            h,
+            ::std::mem::transmute(frame.format()),
+            w,
+            h,
+            AVPixelFormat::AV_PIX_FMT_RGB24,
+            SWS_BILINEAR as i32,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null(),
+        );
+        if ctx == ptr::null_mut() {
+            return None;
+        }
+
+        let mut buf = vec![0u8; (w * h * 3) as usize];
+        let mut dst_data: [*mut u8; 4] = [buf.as_mut_ptr(), ptr::null_mut(), ptr::null_mut(), ptr::null_mut()];
+        let mut dst_linesize: [i32; 4] = [w * 3, 0, 0, 0];
+        sws_scale(
+            ctx,
+            (*frame.raw).data.as_ptr() as *const *const u8,
+            (*frame.raw).linesize.as_ptr(),
+            0,
+            h,
+            dst_data.as_mut_ptr(),
+            dst_linesize.as_mut_ptr(),
+        );
+        sws_freeContext(ctx);
+        Some(buf)
+    }
+}
+
+/// Scales `(w, h)` down to fit within `(max_w, max_h)`, preserving aspect
+/// ratio, so a large remote resolution doesn't open an oversized window.
+/// Returns `(w, h)` unchanged if it already fits.
+fn fit_to_bounds(w: i32, h: i32, max_w: i32, max_h: i32) -> (i32, i32) {
+    if w <= max_w && h <= max_h {
+        return (w, h);
+    }
+    let scale = (max_w as f64 / w as f64).min(max_h as f64 / h as f64);
+    (((w as f64 * scale) as i32).max(1), ((h as f64 * scale) as i32).max(1))
+}
+
+/// A compressed video codec a connection can negotiate.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    H264,
+    H265,
+    VP9,
+}
+
+impl Codec {
+    /// Parses the 4-byte FourCC sent during the per-connection handshake.
+    pub fn from_fourcc(tag: &[u8; 4]) -> Option<Codec> {
+        match tag {
+            b"h264" => Some(Codec::H264),
+            b"hvc1" | b"hev1" => Some(Codec::H265),
+            b"vp09" => Some(Codec::VP9),
+            _ => None,
+        }
+    }
+
+    fn to_av_codec_id(self) -> AVCodecID {
+        match self {
+            Codec::H264 => AVCodecID::AV_CODEC_ID_H264,
+            Codec::H265 => AVCodecID::AV_CODEC_ID_HEVC,
+            Codec::VP9 => AVCodecID::AV_CODEC_ID_VP9,
+        }
+    }
+}
+
+/// A Window which can draw with raw compressed video data directly,
+/// decoding whichever codec was negotiated for the connection.
+pub struct VideoWindow {
     name: String,
+    codec: Codec,
     video: Option<VideoSubsystem>,
     context: *mut AVCodecContext,
     frame: YUVFrame,
     window: Option<YUVWindow>,
+    recorder: Option<Recorder>,
+    converter: Option<Converter>,
+    max_size: (i32, i32),
 }
 
-impl H264Window {
-    /// Constructs a new `H264Window`.
-    pub fn new(name: &str, video: VideoSubsystem) -> H264Window {
+impl VideoWindow {
+    /// Constructs a new `VideoWindow` decoding `codec`, whose displayed
+    /// size will be scaled down to fit within `max_w`/`max_h` (typically
+    /// the screen size) while preserving the source aspect ratio. Returns
+    /// `None` if this build of ffmpeg has no decoder for `codec`.
+    pub fn new(
+        name: &str,
+        codec: Codec,
+        video: VideoSubsystem,
+        max_w: i32,
+        max_h: i32,
+    ) -> Option<VideoWindow> {
         unsafe {
-            let codec = avcodec_find_decoder(AVCodecID::AV_CODEC_ID_H264);
-            assert!(codec != ptr::null_mut());
-            let context = avcodec_alloc_context3(codec);
+            let av_codec = avcodec_find_decoder(codec.to_av_codec_id());
+            if av_codec == ptr::null_mut() {
+                return None;
+            }
+            let context = avcodec_alloc_context3(av_codec);
             assert!(context != ptr::null_mut());
-            let ret = avcodec_open2(context, codec, ptr::null_mut());
+            let ret = avcodec_open2(context, av_codec, ptr::null_mut());
             assert!(ret >= 0);
 
-            H264Window {
+            Some(VideoWindow {
                 name: String::from(name),
+                codec,
                 video: Some(video),
                 context,
                 frame: YUVFrame::new().unwrap(),
                 window: None,
-            }
+                recorder: None,
+                converter: None,
+                max_size: (max_w, max_h),
+            })
         }
     }
 
-    /// Draws canvas with given H.264 data.
-    pub fn draw(&mut self, data: &mut [u8]) {
-        self.decode(data);
+    /// Draws canvas with given compressed video data. Returns `Ok(())` whether or not
+    /// a frame was actually produced: the decoder may swallow a packet
+    /// (e.g. SPS/PPS) without emitting one, in which case the previous
+    /// frame stays on screen. An error is returned only for a genuine
+    /// decode failure or a decoded frame that can't be converted for display.
+    pub fn draw(&mut self, data: &mut [u8]) -> Result<(), Box<Error>> {
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.write_packet(data);
+        }
+        if !self.decode(data)? {
+            return Ok(());
+        }
+
+        let (max_w, max_h) = self.max_size;
+        let (dst_w, dst_h) = fit_to_bounds(self.frame.width(), self.frame.height(), max_w, max_h);
+        let needs_conversion =
+            self.frame.format() != AVPixelFormat::AV_PIX_FMT_YUV420P as i32 || (dst_w, dst_h) != (self.frame.width(), self.frame.height());
+
+        let frame = if needs_conversion {
+            let stale = match self.converter {
+                Some(ref c) => !c.matches(self.frame.format(), self.frame.width(), self.frame.height(), dst_w, dst_h),
+                None => true,
+            };
+            if stale {
+                self.converter = Converter::new(self.frame.format(), self.frame.width(), self.frame.height(), dst_w, dst_h);
+            }
+            match self.converter {
+                Some(ref mut c) => c.convert(&self.frame),
+                None => {
+                    return Err(format!(
+                        "{:?} decoder produced pixel format {} at {}x{}, which couldn't be converted for display",
+                        self.codec,
+                        self.frame.format(),
+                        self.frame.width(),
+                        self.frame.height()
+                    ).into())
+                }
+            }
+        } else {
+            &self.frame
+        };
+
         if self.window.is_none() {
-            self.window = YUVWindow::new(
-                &self.name,
-                self.frame.width(),
-                self.frame.height(),
-                self.video.take().unwrap(),
-            ).ok();
+            self.window = YUVWindow::new(&self.name, dst_w, dst_h, self.video.take().unwrap()).ok();
+        }
+        self.window.as_mut().unwrap().update(frame);
+        Ok(())
+    }
+
+    /// Flushes the decoder, draining and displaying any frames it was
+    /// still holding onto. Called once a connection closes.
+    pub fn flush(&mut self) {
+        unsafe {
+            avcodec_send_packet(self.context, ptr::null_mut());
+        }
+        while unsafe { avcodec_receive_frame(self.context, self.frame.raw) } >= 0 {
+            if let Some(win) = self.window.as_mut() {
+                win.update(&self.frame);
+            }
         }
-        self.window.as_mut().unwrap().update(&self.frame);
+    }
+
+    /// Starts remuxing the incoming compressed packets into an MP4 file at
+    /// `path`, alongside decoding and display. Only H.264 is supported;
+    /// other codecs are logged and ignored.
+    pub fn start_recording(&mut self, path: &str) {
+        if self.codec != Codec::H264 {
+            eprintln!("{}: recording a {:?} stream isn't supported, skipping", self.name, self.codec);
+            return;
+        }
+        let extradata = unsafe {
+            let ctx = *self.context;
+            if ctx.extradata.is_null() {
+                &[]
+            } else {
+                slice::from_raw_parts(ctx.extradata, ctx.extradata_size as usize)
+            }
+        };
+        self.recorder = Recorder::new(
+            path,
+            self.codec.to_av_codec_id(),
+            self.frame.width(),
+            self.frame.height(),
+            extradata,
+        );
+    }
+
+    /// Stops recording, finalizing the MP4 file.
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    /// Returns `true` if this window is currently being recorded.
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
     }
 
     /// Hides the window.
@@ -207,21 +482,62 @@ impl H264Window {
         self.window.is_some()
     }
 
-    /// Decodes the video frame from data into picture.
-    fn decode(&mut self, data: &mut [u8]) {
+    /// Returns the SDL window id, used to match GUI events to this window.
+    pub fn id(&self) -> Option<u32> {
+        self.window.as_ref().map(|w| w.id())
+    }
+
+    /// Returns the decoded frame's native resolution, as opposed to the
+    /// (possibly scaled) on-screen window size returned by `size()`.
+    pub fn native_size(&self) -> (i32, i32) {
+        (self.frame.width(), self.frame.height())
+    }
+
+    /// Computes a compact BlurHash placeholder string from the most
+    /// recently decoded frame, so a controlling UI can show a lightweight
+    /// preview before the full video stream arrives.
+    pub fn blurhash(&self) -> Option<String> {
+        let rgb = frame_to_rgb(&self.frame)?;
+        Some(blurhash::encode(
+            &rgb,
+            self.frame.width() as usize,
+            self.frame.height() as usize,
+            4,
+            3,
+        ))
+    }
+
+    /// Decodes the video frame from data into picture. Returns `Ok(true)`
+    /// if a frame was produced, `Ok(false)` if the decoder needs more
+    /// input before it can emit one (`EAGAIN`) or has been fully drained
+    /// (`EOF`), and `Err` on a genuine decode failure.
+    fn decode(&mut self, data: &mut [u8]) -> Result<bool, Box<Error>> {
         unsafe {
             let pkt = av_packet_alloc();
             av_init_packet(pkt);
             av_packet_from_data(pkt, data.as_mut_ptr(), data.len() as i32);
             let ret = avcodec_send_packet(self.context, pkt);
-            assert!(ret >= 0);
+            if ret < 0 && ret != AVERROR_EAGAIN {
+                return Err(format!("avcodec_send_packet failed: {}", ret).into());
+            }
+
             let ret = avcodec_receive_frame(self.context, self.frame.raw);
-            assert!(ret >= 0);
+            if ret == AVERROR_EAGAIN || ret == AVERROR_EOF {
+                return Ok(false);
+            }
+            if ret < 0 {
+                return Err(format!("avcodec_receive_frame failed: {}", ret).into());
+            }
+            Ok(true)
         }
     }
 }
 
-impl Drop for H264Window {
+/// `AVERROR(EAGAIN)`: the decoder consumed the packet but needs more
+/// input before it can produce a frame.
+const AVERROR_EAGAIN: i32 = -11;
+
+impl Drop for VideoWindow {
     fn drop(&mut self) {
         unsafe {
             avcodec_free_context(&mut self.context);